@@ -2,6 +2,7 @@
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate clap;
 use serde_json::{Value};
 
 // For reading test settings file
@@ -11,24 +12,99 @@ use std::io::prelude::*;
 // For running cargo test
 use std::process::Command;
 use std::process::Output;
+use std::time::Instant;
+
+use clap::{App, Arg};
 
 fn main() {
-    if std::env::args().len() == 1 {
-        eprintln!("Usage: test-runner <path-to-settings.json>");
-        std::process::exit(1);
-    }
+    let matches = App::new("test-runner")
+        .about("Runs configured cargo test suites and reports gradescope-style results")
+        .arg(Arg::with_name("settings")
+            .help("Path to the settings JSON file")
+            .required(true)
+            .index(1))
+        .arg(Arg::with_name("only")
+            .long("only")
+            .value_name("SUITE_NUMBER")
+            .help("Only run the suite with this number (repeatable)")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+        .arg(Arg::with_name("filter")
+            .long("filter")
+            .value_name("SUBSTR")
+            .help("Further narrow test names within selected suites")
+            .takes_value(true))
+        .arg(Arg::with_name("target")
+            .long("target")
+            .value_name("DIR")
+            .help("Override the target directory from the settings file")
+            .takes_value(true))
+        .arg(Arg::with_name("list")
+            .long("list")
+            .help("List the discovered suites and filters without running them"))
+        .arg(Arg::with_name("baseline")
+            .long("baseline")
+            .value_name("PATH")
+            .help("Compare against a previously emitted results JSON to report regressions/fixes")
+            .takes_value(true))
+        .get_matches();
 
     // Read autograder settings
-    let settings_file_path = std::env::args().nth(1).unwrap();
-    let settings = read_settings(&settings_file_path);
+    let settings_file_path = matches.value_of("settings").unwrap();
+    let mut settings = read_settings(settings_file_path);
+
+    if let Some(target) = matches.value_of("target") {
+        settings.target = target.to_string();
+    }
+
+    if let Some(only) = matches.values_of("only") {
+        let only: Vec<&str> = only.collect();
+        settings.suites.retain(|suite| only.contains(&suite.number.as_str()));
+    }
+
+    let extra_filter = matches.value_of("filter");
+
+    if matches.is_present("list") {
+        for suite in &settings.suites {
+            println!("{}: {} (filter: \"{}\")", suite.number, suite.name, suite.filter);
+        }
+        return;
+    }
 
     let mut results = Results::new();
 
     // Run through each of the suites
-    for suite in settings.suites {
-        cargo_test(&mut results, &settings.target, &suite);
+    for suite in &settings.suites {
+        cargo_test(&mut results, &settings.target, suite, extra_filter);
     }
 
+    let mut summary = Summary::new(&settings.suites, &results.tests);
+
+    if let Some(baseline_path) = matches.value_of("baseline") {
+        match read_results(baseline_path) {
+            Ok(baseline) => {
+                let baseline_state: std::collections::HashMap<String, bool> = baseline.tests.iter()
+                    .map(|test| (test.number.clone() + "|" + &test.name, test.passed))
+                    .collect();
+                for test in &results.tests {
+                    let key = test.number.clone() + "|" + &test.name;
+                    let label = format!("{} - {}", test.number, test.name);
+                    match baseline_state.get(&key) {
+                        Some(&true) if !test.passed => summary.regressions.push(label),
+                        Some(&false) if test.passed => summary.fixes.push(label),
+                        _ => {}
+                    }
+                }
+            },
+            Err(err) => {
+                results.output += &format!("warning: ignoring baseline: {}\n", err);
+            }
+        }
+    }
+
+    results.summary = summary;
+
     // Print results back in gradescope format
     let serialized = serde_json::to_string(&results).unwrap();
     println!("{}", serialized);
@@ -49,6 +125,16 @@ fn read_settings(path: &str) -> Settings {
     settings
 }
 
+fn read_results(path: &str) -> Result<Results, String> {
+    let mut results_file = File::open(path)
+        .map_err(|err| format!("Could not open baseline results file {}: {}", path, err))?;
+    let mut contents = String::new();
+    results_file.read_to_string(&mut contents)
+        .map_err(|err| format!("Could not read baseline results file {}: {}", path, err))?;
+    serde_json::from_str(&contents)
+        .map_err(|err| format!("Could not parse baseline results file {}: {}", path, err))
+}
+
 #[derive(Debug, Deserialize)]
 struct Settings {
     target: String,
@@ -61,25 +147,37 @@ struct Suite {
     name: String,
     points: f64,
     filter: String,
+    #[serde(default)]
+    time_limit: Option<f64>,
+    #[serde(default = "default_suite_kind")]
+    kind: String,
 }
 
-#[derive(Debug,Serialize)]
+fn default_suite_kind() -> String {
+    String::from("unit")
+}
+
+#[derive(Debug,Serialize,Deserialize)]
 struct Test {
     number: String,
     name: String,
     score: f64,
     max_score: f64,
     output: String,
+    time: Option<f64>,
+    passed: bool,
 }
 
 impl Test {
-    fn new(number: String, name: String, score: f64, output: String) -> Test {
+    fn new(number: String, name: String, score: f64, output: String, time: Option<f64>, passed: bool) -> Test {
         Test {
             number,
             name,
             score,
-            output, 
+            output,
             max_score: 1.0f64,
+            time,
+            passed,
         }
     }
 
@@ -90,6 +188,8 @@ impl Test {
             score: round(self.score * factor),
             max_score: round(self.max_score * factor),
             output: self.output.clone(),
+            time: self.time,
+            passed: self.passed,
         }
     }
 }
@@ -98,10 +198,11 @@ fn round(input: f64) -> f64 {
     (input * 100.0).round() / 100.0
 }
 
-#[derive(Debug,Serialize)]
+#[derive(Debug,Serialize,Deserialize)]
 struct Results {
     tests: Vec<Test>,
     output: String,
+    summary: Summary,
 }
 
 impl Results {
@@ -109,7 +210,66 @@ impl Results {
         Results {
             tests: Vec::new(),
             output: String::from(""),
+            summary: Summary::empty(),
+        }
+    }
+}
+
+#[derive(Debug,Serialize,Deserialize)]
+struct SuiteSummary {
+    number: String,
+    name: String,
+    passed: u64,
+    failed: u64,
+    total: u64,
+    earned_points: f64,
+    max_points: f64,
+}
+
+#[derive(Debug,Serialize,Deserialize)]
+struct Summary {
+    suites: Vec<SuiteSummary>,
+    earned_points: f64,
+    max_points: f64,
+    regressions: Vec<String>,
+    fixes: Vec<String>,
+}
+
+impl Summary {
+    fn empty() -> Summary {
+        Summary {
+            suites: Vec::new(),
+            earned_points: 0.0,
+            max_points: 0.0,
+            regressions: Vec::new(),
+            fixes: Vec::new(),
+        }
+    }
+
+    fn new(suites: &[Suite], tests: &[Test]) -> Summary {
+        let mut summary = Summary::empty();
+        for suite in suites {
+            let prefix = suite.number.clone() + ".";
+            let in_suite: Vec<&Test> = tests.iter().filter(|t| t.number.starts_with(&prefix)).collect();
+            let passed = in_suite.iter().filter(|t| t.passed).count() as u64;
+            let total = in_suite.len() as u64;
+            let earned_points = round(in_suite.iter().map(|t| t.score).sum());
+            let max_points = round(in_suite.iter().map(|t| t.max_score).sum());
+            summary.earned_points += earned_points;
+            summary.max_points += max_points;
+            summary.suites.push(SuiteSummary {
+                number: suite.number.clone(),
+                name: suite.name.clone(),
+                passed,
+                failed: total - passed,
+                total,
+                earned_points,
+                max_points,
+            });
         }
+        summary.earned_points = round(summary.earned_points);
+        summary.max_points = round(summary.max_points);
+        summary
     }
 }
 
@@ -120,29 +280,82 @@ impl Results {
  * As such, we need to run the tests, batch the results, and then apply point values
  * retroactively to each test before adding them to the top-level Results object.
  */
-fn cargo_test(results: &mut Results, path: &str, suite: &Suite) {
+fn cargo_test(results: &mut Results, path: &str, suite: &Suite, extra_filter: Option<&str>) {
+    let start = Instant::now();
+    let mut args: Vec<&str> = vec!["test"];
+    if suite.kind == "doc" {
+        args.push("--doc");
+    }
+    args.push(&suite.filter);
+    args.push("--"); // the following args give json output
+    args.push("-Z");
+    args.push("unstable-options");
+    args.push("--format=json");
+    args.push("--report-time"); // needed so "ok"/"failed" events carry exec_time
+
     let output = Command::new("cargo")
                    .current_dir(path)
-                   .args(&[
-                       "test", 
-                       &suite.filter,
-                       "--", // the following args give json output
-                       "-Z",
-                       "unstable-options",
-                       "--format=json"
-                   ])
+                   .args(&args)
                    .env("RUN_TEST_TASKS", "1") // run serially for consistency
                    .output();
+    let elapsed = start.elapsed();
+    results.output += &format!("suite {} ran in {:.2}s\n", suite.name, elapsed.as_secs_f64());
 
     match output {
         Ok(output) => {
             let mut batch: Vec<Test> = Vec::new();
             let mut count = 1;
-            for line in output_to_json(&output) {
-                let number = suite.number.clone() + "." + &count.to_string();
-                if let Some(test) = filter_test_output(&line, &number, &suite.name) {
-                    batch.push(test);
-                    count += 1;
+            let mut observed_results = 0;
+            let mut reported_total = 0;
+            let mut saw_suite_record = false;
+            let (parsed, skipped) = output_to_json(&output);
+            if skipped > 0 {
+                results.output += &format!(
+                    "warning: suite {} had {} unparseable token(s) in its JSON output\n",
+                    suite.name, skipped
+                );
+            }
+            for line in parsed {
+                if is_test_result(&line) {
+                    if line["event"] != "ignored" {
+                        observed_results += 1;
+                    }
+                    let number = suite.number.clone() + "." + &count.to_string();
+                    if let Some(extra_filter) = extra_filter {
+                        let raw_name = line["name"].to_string().replace("\"", "");
+                        if !raw_name.contains(extra_filter) {
+                            continue;
+                        }
+                    }
+                    if let Some(test) = filter_test_output(&line, &number, &suite.name) {
+                        batch.push(test);
+                        count += 1;
+                    }
+                } else if line["type"] == "suite" && (line["event"] == "ok" || line["event"] == "failed") {
+                    // A target crate can have multiple test binaries (unit tests plus
+                    // each file under tests/), each emitting its own suite record, so
+                    // these must be summed rather than overwritten.
+                    saw_suite_record = true;
+                    reported_total += line["passed"].as_u64().unwrap_or(0);
+                    reported_total += line["failed"].as_u64().unwrap_or(0);
+                }
+            }
+            if saw_suite_record && reported_total != observed_results {
+                results.output += &format!(
+                    "warning: suite {} reported {} tests but only parsed {} test events\n",
+                    suite.name, reported_total, observed_results
+                );
+            }
+            if let Some(time_limit) = suite.time_limit {
+                for test in &mut batch {
+                    if test.time.map_or(false, |time| time > time_limit) {
+                        test.score = 0.0;
+                        test.passed = false;
+                        if !test.output.is_empty() {
+                            test.output.push_str("; ");
+                        }
+                        test.output.push_str(&format!("exceeded time limit of {}s", time_limit));
+                    }
                 }
             }
             if batch.len() > 0 {
@@ -154,27 +367,52 @@ fn cargo_test(results: &mut Results, path: &str, suite: &Suite) {
             }
         },
         Err(err) => {
-            results.output = format!("{}", err);
+            results.output += &format!("{}", err);
         }
     }
 }
 
-fn output_to_json(output: &Output) -> Vec<serde_json::Value> {
-    let mut json = Vec::new();
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    for line in stdout.split("\n") {
-        let v = serde_json::from_str::<Value>(line);
-        if let Ok(v) = v {
-            json.push(v);
+fn is_test_result(line: &serde_json::Value) -> bool {
+    line["type"] == "test" && line["event"] != "started"
+}
+
+/*
+ * libtest's `--format=json` output is one JSON value per line, but a test's captured
+ * stdout can itself contain newlines, which would corrupt a naive line-split. Walking
+ * the byte stream with a `Deserializer` instead lets serde_json find value boundaries
+ * on its own. A `StreamDeserializer` gives up for good after its first error, so on a
+ * parse failure we resynchronize by skipping ahead to the next newline and restarting
+ * from there, rather than losing every value after the bad byte.
+ */
+fn output_to_json(output: &Output) -> (Vec<serde_json::Value>, usize) {
+    let mut values = Vec::new();
+    let mut skipped = 0;
+    let mut remaining: &[u8] = &output.stdout;
+
+    while !remaining.is_empty() {
+        let mut stream = serde_json::Deserializer::from_slice(remaining).into_iter::<Value>();
+        match stream.next() {
+            Some(Ok(value)) => {
+                values.push(value);
+                remaining = &remaining[stream.byte_offset()..];
+            }
+            Some(Err(_)) => {
+                skipped += 1;
+                let past_error = &remaining[stream.byte_offset().max(1)..];
+                remaining = match past_error.iter().position(|&b| b == b'\n') {
+                    Some(pos) => &past_error[pos + 1..],
+                    None => &[],
+                };
+            }
+            None => break,
         }
     }
-    json
+
+    (values, skipped)
 }
 
 fn filter_test_output(line: &serde_json::Value, number: &str, prefix: &str) -> Option<Test> {
-    if line["type"] == "test" && line["event"] != "started" {
-        // noop
-    } else {
+    if !is_test_result(line) {
         return None;
     }
 
@@ -182,7 +420,8 @@ fn filter_test_output(line: &serde_json::Value, number: &str, prefix: &str) -> O
     let score = if passed { 1.0 } else { 0.0 };
     let name = prefix.to_owned() + " - " + &line["name"].to_string().replace("\"", "");
     let output = if passed { String::from("") } else { unescape(&line["stdout"].to_string()) };
-    Some(Test::new(number.to_string(), name, score, output))
+    let time = line["exec_time"].as_f64();
+    Some(Test::new(number.to_string(), name, score, output, time, passed))
 }
 
 /*